@@ -0,0 +1,64 @@
+use std::sync::mpsc;
+
+use pool::{Pool, WorkerId};
+
+impl Pool {
+    /// Run `f` exactly once on each of the pool's worker threads,
+    /// returning the results indexed by `WorkerId`.
+    ///
+    /// This is the low-level `execute` primitive wearing a thin
+    /// wrapper: `execute`'s `gen_fn` is already called exactly
+    /// `self.n_threads()` times to build one closure per worker, so
+    /// `broadcast` just has each of those closures call `f(id)` and
+    /// ship the result back over a channel tagged with the worker's
+    /// index.
+    ///
+    /// Unlike the element-oriented `for_`/`map`/`unordered_map`
+    /// family, which hand out individual elements of an iterator to
+    /// whichever worker happens to be free, `broadcast` guarantees
+    /// `f` runs exactly once per worker. That makes it the right tool
+    /// for per-thread initialisation: seeding thread-local RNGs,
+    /// opening one connection per worker, warming a per-thread cache.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use simple_parallel::Pool;
+    ///
+    /// let mut pool = Pool::new(4);
+    ///
+    /// let ids = pool.broadcast(|id| id.index());
+    /// assert_eq!(ids, vec![0, 1, 2, 3]);
+    /// ```
+    pub fn broadcast<F, T>(&mut self, ref f: F) -> Vec<T>
+        where F: Fn(WorkerId) -> T + Sync,
+              T: Send
+    {
+        let n = self.n_threads();
+        let (tx, rx) = mpsc::channel();
+
+        unsafe {
+            let handle = self.execute(
+                (),
+                move |_| {
+                    let tx = tx.clone();
+                    move |id: WorkerId| {
+                        let _ = tx.send((id.index(), f(id)));
+                    }
+                },
+                |_| {});
+
+            handle.wait();
+        }
+
+        let mut results: Vec<Option<T>> = (0..n).map(|_| None).collect();
+        for _ in 0..n {
+            let (idx, value) = match rx.recv() {
+                Ok(pair) => pair,
+                Err(mpsc::RecvError) => panic!("simple_parallel::broadcast: closure panicked"),
+            };
+            results[idx] = Some(value);
+        }
+        results.into_iter().map(|value| value.unwrap()).collect()
+    }
+}
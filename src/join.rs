@@ -0,0 +1,52 @@
+use pool::{self, Pool};
+
+impl Pool {
+    /// Run `oper_a` on another worker while running `oper_b` on the
+    /// calling thread, then block until both are done and return
+    /// both results.
+    ///
+    /// This is the fork-join primitive for divide-and-conquer
+    /// algorithms -- parallel quicksort/mergesort, tree walks -- that
+    /// recursively call `join` again, which the flat `for_`/`map`
+    /// iterators can't express.
+    ///
+    /// `oper_a` and its result must be `Send`, since `oper_a` may run
+    /// on a different thread than the one that called `join`; `oper_b`
+    /// runs on the calling thread, so it has no such restriction.
+    ///
+    /// If either closure panics, the panic is propagated to the
+    /// caller of `join` only after *both* closures have finished (or
+    /// panicked): `oper_a` is submitted via `spawn`, and the
+    /// `JobFuture` it returns blocks in its destructor until `oper_a`
+    /// has settled, so even unwinding through a panic in `oper_b`
+    /// still waits for `oper_a` before the panic continues upward.
+    ///
+    /// # Oversubscription
+    ///
+    /// If a worker thread itself calls `join` (because `oper_a` or
+    /// `oper_b` of an outer `join` call `join` again), naively
+    /// submitting the inner `oper_a` back into the pool and blocking
+    /// on it could deadlock if every worker is already busy running
+    /// an outer `join`. To avoid that, `join` detects when it is
+    /// already running on one of the pool's own worker threads and,
+    /// in that case, simply runs `oper_a` then `oper_b` inline, in
+    /// sequence, on the current thread instead of submitting `oper_a`
+    /// back into the pool. In that inline case a panic in `oper_a`
+    /// propagates straight away, and `oper_b` is not run.
+    pub fn join<A, B, RA, RB>(&self, oper_a: A, oper_b: B) -> (RA, RB)
+        where A: Send + FnOnce() -> RA,
+              B: FnOnce() -> RB,
+              RA: Send
+    {
+        if pool::is_worker_thread() {
+            let a = oper_a();
+            let b = oper_b();
+            (a, b)
+        } else {
+            let future = self.spawn(oper_a);
+            let b = oper_b();
+            let a = future.wait();
+            (a, b)
+        }
+    }
+}
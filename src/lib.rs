@@ -3,9 +3,15 @@
 
 use std::thread;
 
+mod pool;
 mod maps;
+mod spawn;
+mod broadcast;
+mod join;
 
-pub use maps::{unordered_map, UnorderedParMap, map, ParMap};
+pub use pool::{Pool, WorkerId};
+pub use maps::{UnorderedParMap, ParMap};
+pub use spawn::JobFuture;
 
 /// Execute `f` on each element of `iter`, in their own `scoped`
 /// thread.
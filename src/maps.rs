@@ -0,0 +1,523 @@
+use std::collections::{BinaryHeap, VecDeque};
+use std::iter::IntoIterator;
+use std::cmp::Ordering;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+use pool::{Pool, WorkerId, JobHandle};
+
+/// A crude work-stealing deque: grains are pushed onto the back when
+/// it is seeded, and the owning worker pops whole grains from the
+/// back (LIFO, so it tends to work on data
+/// that's still warm in cache), while other workers that have run
+/// dry steal whole grains from the front (FIFO, so a big backlog
+/// gets spread out rather than repeatedly raided from the same end).
+/// A grain (a `Vec<T>`) is the atomic unit of stealing; a worker
+/// unpacks its own grain one element at a time before asking for
+/// another.
+///
+/// This is a `Mutex`-guarded `VecDeque` rather than a lock-free
+/// Chase-Lev deque: the elements flowing through here are at least a
+/// whole grain's worth of work, so the lock is never the bottleneck.
+struct Deque<T> {
+    inner: Mutex<VecDeque<Vec<T>>>,
+}
+impl<T> Deque<T> {
+    fn new() -> Deque<T> {
+        Deque { inner: Mutex::new(VecDeque::new()) }
+    }
+    fn push_grain(&self, grain: Vec<T>) {
+        if !grain.is_empty() {
+            self.inner.lock().unwrap().push_back(grain);
+        }
+    }
+    fn pop_own(&self) -> Option<Vec<T>> {
+        self.inner.lock().unwrap().pop_back()
+    }
+    fn steal(&self) -> Option<Vec<T>> {
+        self.inner.lock().unwrap().pop_front()
+    }
+}
+
+/// Slice `iter` into grains of `grain_size` elements and seed
+/// `deques` round-robin, returning the total number of elements
+/// distributed.
+///
+/// This runs synchronously on the calling thread, draining `iter`
+/// into owned grains *before* any worker is dispatched: no worker
+/// sees a single element until `iter` is fully consumed and sliced.
+/// An iterator that blocks or never terminates therefore wedges here
+/// before any parallelism begins, and the whole input (plus its
+/// grains) is held in memory at once. A design that pulled grains
+/// from `iter` lazily, on demand from an idle worker, would not have
+/// either limitation, but would need `iter` itself to be shared
+/// behind a lock rather than pre-sliced up front.
+fn seed_round_robin<I: Iterator>(iter: I, deques: &[Arc<Deque<I::Item>>], grain_size: usize) -> usize {
+    let mut total = 0;
+    let mut iter = iter.peekable();
+    let mut home = 0;
+    while iter.peek().is_some() {
+        let grain: Vec<_> = iter.by_ref().take(grain_size).collect();
+        total += grain.len();
+        deques[home].push_grain(grain);
+        home = (home + 1) % deques.len();
+    }
+    total
+}
+
+/// A tiny xorshift PRNG, seeded per-worker, used only to pick a
+/// (pseudo-)random victim to steal from; this isn't
+/// security-sensitive so a full `rand::Rng` is overkill.
+struct XorShift { state: u32 }
+impl XorShift {
+    fn new(seed: usize) -> XorShift {
+        XorShift { state: (seed as u32) | 1 }
+    }
+    fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+}
+
+/// A worker's view of the work-stealing scheduler: a cursor over its
+/// own deque plus the local buffer (the most recently pulled grain)
+/// it's currently unpacking one element at a time.
+struct Worker<T> {
+    home: usize,
+    deques: Vec<Arc<Deque<T>>>,
+    pending: Arc<AtomicUsize>,
+    rng: XorShift,
+    local: Vec<T>,
+}
+impl<T> Worker<T> {
+    fn new(home: usize, deques: Vec<Arc<Deque<T>>>, pending: Arc<AtomicUsize>) -> Worker<T> {
+        Worker {
+            rng: XorShift::new(home),
+            home: home,
+            deques: deques,
+            pending: pending,
+            local: Vec::new(),
+        }
+    }
+
+    /// Try to refill `self.local` with a whole grain: first from this
+    /// worker's own deque, then, if that's empty, by stealing from a
+    /// random victim. Returns `false` only once `pending` has hit
+    /// zero and every deque came up dry, meaning every grain has been
+    /// claimed by some worker.
+    ///
+    /// `pending` is decremented by the whole grain's length as soon
+    /// as it's claimed here, not as each element is later processed:
+    /// that way a worker whose closure panics partway through a grain
+    /// still accounts for the rest of it, so `pending` can still
+    /// reach zero and the other workers won't spin in here forever
+    /// waiting for grains that will never be finished.
+    fn refill(&mut self) -> bool {
+        loop {
+            if let Some(grain) = self.deques[self.home].pop_own() {
+                self.pending.fetch_sub(grain.len(), AtomicOrdering::SeqCst);
+                self.local = grain;
+                return true;
+            }
+            if self.deques.len() > 1 {
+                let start = self.rng.next_u32() as usize % self.deques.len();
+                for i in 0..self.deques.len() {
+                    let victim = (start + i) % self.deques.len();
+                    if victim == self.home { continue }
+                    if let Some(grain) = self.deques[victim].steal() {
+                        self.pending.fetch_sub(grain.len(), AtomicOrdering::SeqCst);
+                        self.local = grain;
+                        return true;
+                    }
+                }
+            }
+            if self.pending.load(AtomicOrdering::SeqCst) == 0 {
+                return false;
+            }
+            // Someone else is still holding work; yield and try again.
+            ::std::thread::yield_now();
+        }
+    }
+
+    /// The next element for this worker to process, pulling a fresh
+    /// grain (from its own deque, or by stealing) whenever its local
+    /// buffer runs dry. Returns `None` once the whole job is done.
+    fn next(&mut self) -> Option<T> {
+        loop {
+            if let Some(item) = self.local.pop() {
+                return Some(item);
+            }
+            if !self.refill() {
+                return None;
+            }
+        }
+    }
+}
+
+impl Pool {
+    /// Execute `f` on each element of `iter`.
+    ///
+    /// This panics if `f` panics, although the precise time and
+    /// number of elements consumed after the element that panics is
+    /// not specified.
+    ///
+    /// # Limitations
+    ///
+    /// `iter` is drained eagerly on the calling thread, into grains,
+    /// before any worker starts -- it isn't pulled incrementally as
+    /// workers become free. An iterator that blocks or never
+    /// terminates will wedge here before any parallelism begins, and
+    /// the full input, plus its grains, is held in memory at once.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use simple_parallel::Pool;
+    ///
+    /// let mut pool = Pool::new(4);
+    ///
+    /// let mut v = [0; 8];
+    ///
+    /// // set each element, in parallel
+    /// pool.for_(&mut v, |element| *element = 3);
+    ///
+    /// assert_eq!(v, [3; 8]);
+    /// ```
+    pub fn for_<Iter: IntoIterator, F>(&mut self, iter: Iter, ref f: F)
+        where Iter::Item: Send,
+              Iter: Send,
+              F: Fn(Iter::Item) + Sync
+
+    {
+        let n = self.n_threads();
+        let deques: Vec<_> = (0..n).map(|_| Arc::new(Deque::new())).collect();
+        let pending = Arc::new(AtomicUsize::new(0));
+        pending.store(seed_round_robin(iter.into_iter(), &deques, self.grain_size()), AtomicOrdering::SeqCst);
+
+        // `main_fn` below must not return until every worker has
+        // actually finished running `f` over its share of `iter`,
+        // not just been handed its deque -- that's the only thing
+        // that makes it sound for `handle.wait()` to release `iter`'s
+        // borrow back to the caller. A worker holds its clone of
+        // `barrier_tx` for as long as it's running (dropped on normal
+        // return or panic unwind alike), so blocking until the
+        // channel closes is exactly "wait for every worker to finish".
+        let (barrier_tx, barrier_rx) = mpsc::channel::<()>();
+
+        unsafe {
+            let handle = self.execute(
+                (deques, pending, barrier_tx),
+                |&mut (ref deques, ref pending, ref barrier_tx)| {
+                    let deques = deques.clone();
+                    let pending = pending.clone();
+                    let barrier_tx = barrier_tx.clone();
+                    move |id: WorkerId| {
+                        let _barrier_tx = barrier_tx;
+                        let mut worker = Worker::new(id.index(), deques, pending);
+                        while let Some(elem) = worker.next() {
+                            f(elem);
+                        }
+                    }
+                },
+                |data| {
+                    // drop our own copy of `barrier_tx` (along with
+                    // the rest of `data`) before blocking, so the
+                    // channel closes once every worker's copy does.
+                    drop(data);
+                    let _ = barrier_rx.recv();
+                });
+
+            handle.wait();
+        }
+    }
+
+    /// Execute `f` on each element in `iter` in parallel across the
+    /// pool's threads, with unspecified yield order.
+    ///
+    /// This behaves like `map`, but does not make efforts to ensure
+    /// that the elements are returned in the order of `iter`, hence
+    /// this is cheaper.
+    ///
+    /// The iterator yields `(usize, T)` tuples, where the `usize` is
+    /// the index of the element in the original iterator.
+    ///
+    /// See `for_`'s "Limitations" section: `iter` is drained eagerly,
+    /// on the calling thread, before any worker starts.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use simple_parallel::Pool;
+    ///
+    /// let mut pool = Pool::new(4);
+    ///
+    /// // adjust each element in parallel, and iterate over them as
+    /// // they are generated (or as close to that as possible)
+    /// let f = |i| i + 10;
+    /// for (index, output) in pool.unordered_map(0..8, &f) {
+    ///     // each element is exactly 10 more than its original index
+    ///     assert_eq!(output, index + 10);
+    /// }
+    /// ```
+    pub fn unordered_map<'pool, 'a, I: IntoIterator, F, T>(&'pool mut self, iter: I, f: &'a F)
+        -> UnorderedParMap<'pool, 'a, T>
+        where I: 'a + Send,
+              I::Item: Send + 'a,
+              F: 'a + Sync + Fn(I::Item) -> T,
+              T: Send + 'a
+    {
+        let n = self.n_threads();
+        let deques: Vec<_> = (0..n).map(|_| Arc::new(Deque::new())).collect();
+        let pending = Arc::new(AtomicUsize::new(0));
+        pending.store(seed_round_robin(iter.into_iter().enumerate(), &deques, self.grain_size()), AtomicOrdering::SeqCst);
+
+        let (tx, rx) = mpsc::channel();
+
+        // see `for_` for why `main_fn` needs a real barrier: the
+        // `_guard` field below relies on `main_fn` not returning
+        // until every worker has actually finished, so that dropping
+        // `UnorderedParMap` early (e.g. via `.take(k)`) still blocks
+        // for real completion rather than racing it.
+        let (barrier_tx, barrier_rx) = mpsc::channel::<()>();
+
+        let handle = unsafe {
+            self.execute((deques, pending, barrier_tx),
+                         move |&mut (ref deques, ref pending, ref barrier_tx)| {
+                             let deques = deques.clone();
+                             let pending = pending.clone();
+                             let tx = tx.clone();
+                             let barrier_tx = barrier_tx.clone();
+                             move |id: WorkerId| {
+                                 let _barrier_tx = barrier_tx;
+                                 let mut worker = Worker::new(id.index(), deques, pending);
+                                 while let Some((idx, elem)) = worker.next() {
+                                     let data = f(elem);
+                                     let status = tx.send(Packet {
+                                         idx: idx, data: Some(data)
+                                     });
+                                     // the user disconnected,
+                                     // so there's no point
+                                     // computing more.
+                                     if status.is_err() {
+                                         break
+                                     }
+                                 }
+                             }
+                         },
+                         |data| {
+                             drop(data);
+                             let _ = barrier_rx.recv();
+                         })
+        };
+
+        UnorderedParMap {
+            rx: rx,
+            _guard: handle,
+        }
+    }
+
+    /// Execute `f` on `iter` in parallel across the pool's threads,
+    /// returning an iterator that yields the results in the order of
+    /// the elements of `iter` to which they correspond.
+    ///
+    /// This is a drop-in replacement for `iter.map(f)`, that runs in
+    /// parallel. See `for_`'s "Limitations" section: `iter` is drained
+    /// eagerly, on the calling thread, before any worker starts.
+    ///
+    /// See `unordered_map` if the output order is unimportant.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use simple_parallel::Pool;
+    ///
+    /// let mut pool = Pool::new(4);
+    ///
+    /// // create a vector by adjusting 0..8, in parallel
+    /// let f = |i| i + 10;
+    /// let elements: Vec<_> = pool.map(0..8, &f).collect();
+    ///
+    /// assert_eq!(elements, &[10, 11, 12, 13, 14, 15, 16, 17]);
+    /// ```
+    pub fn map<'pool, 'a, I: IntoIterator, F, T>(&'pool mut self, iter: I, f: &'a F)
+        -> ParMap<'pool, 'a, T>
+        where I: 'a + Send,
+              I::Item: Send + 'a,
+              F: 'a + Sync + Fn(I::Item) -> T,
+              T: Send + 'a
+    {
+        ParMap {
+            unordered: self.unordered_map(iter, f),
+            looking_for: 0,
+            queue: BinaryHeap::new(),
+        }
+    }
+
+    /// Map `iter` with `map_fn` and fold the results together with
+    /// `reduce_fn`, starting each worker's accumulator at `identity`.
+    ///
+    /// Each worker pulls elements (via the same work-stealing deques
+    /// as `for_`/`map`), applies `map_fn`, and folds the result into
+    /// a thread-local accumulator with `reduce_fn`. Once the input is
+    /// exhausted, the partial accumulators from all workers are
+    /// folded together, starting from the first one received, again
+    /// with `reduce_fn`, to produce the final result.
+    ///
+    /// `reduce_fn` must be associative, since folding happens in two
+    /// separate places (within a worker, and across workers), but it
+    /// need not be commutative *unless* you also care about determinism:
+    /// the order in which workers are combined together, and the order
+    /// in which any one worker happens to pull elements from the
+    /// deques, is unspecified. Callers that need a reproducible answer
+    /// should pass a `reduce_fn` that doesn't care about order (e.g.
+    /// addition), not just one that's merely associative.
+    ///
+    /// This avoids the intermediate allocation and ordering overhead
+    /// of `pool.map(iter, &map_fn).collect()` followed by a sequential
+    /// fold, which matters for numeric reductions like dot products
+    /// and sums.
+    ///
+    /// # Panics
+    ///
+    /// This panics if `map_fn` or `reduce_fn` panics on any worker:
+    /// that worker never reaches its final `tx.send(acc)`, so
+    /// collecting its partial accumulator sees a disconnected channel
+    /// and panics in turn.
+    ///
+    /// # Limitations
+    ///
+    /// See `for_`'s "Limitations" section: `iter` is drained eagerly,
+    /// on the calling thread, before any worker starts.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use simple_parallel::Pool;
+    ///
+    /// let mut pool = Pool::new(4);
+    ///
+    /// let v = [1i32, 2, 3, 4, 5, 6, 7, 8];
+    /// let sum_of_squares = pool.map_reduce(v.iter(), &|x: &i32| x * x, 0, &|a, b| a + b);
+    /// assert_eq!(sum_of_squares, 204);
+    /// ```
+    pub fn map_reduce<'pool, 'a, I: IntoIterator, M, Red, T>(
+        &'pool mut self, iter: I, map_fn: &'a M, identity: T, reduce_fn: &'a Red) -> T
+        where I: 'a + Send,
+              I::Item: Send + 'a,
+              M: 'a + Sync + Fn(I::Item) -> T,
+              Red: 'a + Sync + Fn(T, T) -> T,
+              T: Send + Clone + 'a
+    {
+        let n = self.n_threads();
+        let deques: Vec<_> = (0..n).map(|_| Arc::new(Deque::new())).collect();
+        let pending = Arc::new(AtomicUsize::new(0));
+        pending.store(seed_round_robin(iter.into_iter(), &deques, self.grain_size()), AtomicOrdering::SeqCst);
+
+        let (tx, rx) = mpsc::channel();
+
+        unsafe {
+            let handle = self.execute(
+                (deques, pending, identity),
+                move |&mut (ref deques, ref pending, ref identity)| {
+                    let deques = deques.clone();
+                    let pending = pending.clone();
+                    let mut acc = identity.clone();
+                    let tx = tx.clone();
+                    move |id: WorkerId| {
+                        let mut worker = Worker::new(id.index(), deques, pending);
+                        while let Some(elem) = worker.next() {
+                            acc = reduce_fn(acc, map_fn(elem));
+                        }
+                        let _ = tx.send(acc);
+                    }
+                },
+                |_| {});
+
+            handle.wait();
+        }
+
+        let mut partials = (0..n).map(|_| match rx.recv() {
+            Ok(partial) => partial,
+            Err(mpsc::RecvError) => panic!("simple_parallel::map_reduce: closure panicked"),
+        });
+        let first = partials.next().unwrap();
+        partials.fold(first, |acc, partial| reduce_fn(acc, partial))
+    }
+}
+
+struct Packet<T> {
+    // this should be unique for a given instance of `*ParMap`
+    idx: usize,
+    data: Option<T>,
+}
+impl<T> PartialOrd for Packet<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+impl<T> Ord for Packet<T> {
+    // reverse the ordering, to work with the max-heap
+    fn cmp(&self, other: &Self) -> Ordering { other.idx.cmp(&self.idx) }
+}
+impl<T> PartialEq for Packet<T> {
+    fn eq(&self, other: &Self) -> bool { self.idx == other.idx }
+}
+impl<T> Eq for Packet<T> {}
+
+/// A parallel-mapping iterator, that yields elements in the order
+/// they are computed, not the order from which they are yielded by
+/// the underlying iterator.
+pub struct UnorderedParMap<'pool, 'a, T: 'a + Send> {
+    rx: mpsc::Receiver<Packet<T>>,
+    _guard: JobHandle<'pool, 'a>,
+}
+impl<'pool, 'a,T: 'a + Send> Iterator for UnorderedParMap<'pool , 'a, T> {
+    type Item = (usize, T);
+
+    fn next(&mut self) -> Option<(usize, T)> {
+        match self.rx.recv() {
+            Ok(Packet { data: Some(x), idx }) => Some((idx, x)),
+            Ok(Packet { data: None, .. }) => {
+                panic!("simple_parallel::unordered_map: closure panicked")
+            }
+            Err(mpsc::RecvError) => None,
+        }
+    }
+}
+
+/// A parallel-mapping iterator, that yields elements in the order
+/// they are yielded by the underlying iterator.
+pub struct ParMap<'pool, 'a, T: 'a + Send> {
+    unordered: UnorderedParMap<'pool, 'a, T>,
+    looking_for: usize,
+    queue: BinaryHeap<Packet<T>>
+}
+
+impl<'pool, 'a, T: Send + 'a> Iterator for ParMap<'pool, 'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        loop {
+            if self.queue.peek().map_or(false, |x| x.idx == self.looking_for) {
+                // we've found what we want, so lets return it
+
+                let packet = self.queue.pop().unwrap();
+                self.looking_for += 1;
+                match packet.data {
+                    Some(x) => return Some(x),
+                    None => panic!("simple_parallel::map: closure panicked")
+                }
+            }
+            match self.unordered.rx.recv() {
+                // this could be optimised to check for `packet.idx ==
+                // self.looking_for` to avoid the BinaryHeap
+                // interaction if its what we want.
+                Ok(packet) => self.queue.push(packet),
+                // all done
+                Err(mpsc::RecvError) => return None,
+            }
+        }
+    }
+}
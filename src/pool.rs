@@ -1,11 +1,9 @@
-use std::collections::BinaryHeap;
-use std::iter::IntoIterator;
 use std::{marker, mem};
 use std::sync::{mpsc, atomic};
 use std::thread;
 use std::thunk::Invoke;
 
-type JobInner<'b> =  Box<for<'a> Invoke<&'a [mpsc::Sender<Work>], ()> + Send + 'b>;
+type JobInner<'b> =  Box<for<'a> Invoke<&'a [mpsc::Sender<WorkerMsg>], ()> + Send + 'b>;
 struct Job {
     func: JobInner<'static>,
 }
@@ -19,7 +17,12 @@ struct Job {
 ///
 /// The pool currently consists of some number of worker threads
 /// (dynamic, chosen at creation time) along with a single supervisor
-/// thread. The synchronisation overhead is currently very large.
+/// thread. Element-oriented jobs (`for_`, `map`, `unordered_map`) are
+/// distributed with a work-stealing scheme: the calling thread slices the
+/// input into grains and hands each worker its own deque to pop from,
+/// and an idle worker steals grains from another worker's deque
+/// before giving up. This keeps the per-element synchronisation cost
+/// far lower than routing every element through the supervisor.
 ///
 /// # "Short-lifetime"?
 ///
@@ -38,12 +41,11 @@ struct Job {
 /// lifetimes). Higher-level functions will usually wrap or otherwise
 /// hide the handle.
 ///
-/// However, this comes at a cost: for easy of implementation `Pool`
-/// currently only exposes "batch" jobs like `for_` and `map` and
-/// these jobs take control of the whole pool. That is, one cannot
-/// easily incrementally submit arbitrary closures to execute on this
-/// thread pool, which is functionality that `threadpool::ScopedPool`
-/// offers.
+/// Alongside the "batch" jobs like `for_` and `map`, which take
+/// control of the whole pool for the duration of one call, `spawn`
+/// lets you incrementally submit individual closures and collect
+/// their results later, the way `threadpool::ScopedPool` and
+/// workerpool do.
 ///
 /// # Example
 ///
@@ -73,15 +75,48 @@ pub struct Pool {
     job_queue: mpsc::Sender<Option<Job>>,
     job_finished: mpsc::Receiver<Result<(), ()>>,
     n_threads: usize,
+    // one sender per worker's inbox, kept around so `spawn` can push a
+    // task straight to a worker without going via `job_queue`.
+    task_txs: Vec<mpsc::Sender<WorkerMsg>>,
+    next_task_worker: atomic::AtomicUsize,
+    grain_size: usize,
 }
+
+/// The default number of elements handed to a worker per
+/// synchronisation round, for jobs that don't call
+/// `with_grain_size`.
+const DEFAULT_GRAIN_SIZE: usize = 32;
+
+/// The identity of one of a `Pool`'s worker threads.
+///
+/// These are handed to worker-side closures (e.g. via `execute`) so
+/// that they can find the resources (channels, deques, ...) assigned
+/// to them.
 #[derive(Copy)]
-struct WorkerId { n: usize }
+pub struct WorkerId { n: usize }
+impl WorkerId {
+    /// This worker's index, in `0 .. pool.n_threads()`.
+    pub fn index(&self) -> usize { self.n }
+}
 
 type WorkInner<'a> = &'a mut (FnMut(WorkerId) + Send + 'a);
 struct Work {
     func: WorkInner<'static>
 }
 
+/// A single arbitrary closure submitted via `Pool::spawn`.
+pub type TaskInner<'b> = Box<Invoke<(), ()> + Send + 'b>;
+pub struct Task {
+    pub func: TaskInner<'static>,
+}
+
+/// Everything a worker's inbox can receive: either its slice of a
+/// batch job (from `execute`), or a one-off task (from `spawn`).
+enum WorkerMsg {
+    Batch(Work),
+    Task(Task),
+}
+
 /// A token representing a job submitted to the thread pool.
 ///
 /// This ensures that a job is finished before borrowed resources in
@@ -129,6 +164,19 @@ impl Drop for PanicHandler {
         self.tx.send(msg).unwrap();
     }
 }
+thread_local! {
+    static IS_WORKER_THREAD: ::std::cell::Cell<bool> = ::std::cell::Cell::new(false)
+}
+
+/// Whether the calling thread is one of a `Pool`'s own worker
+/// threads, as opposed to, say, the thread that created the `Pool`.
+///
+/// `join` uses this to avoid deadlocking when it is called
+/// recursively from inside a worker.
+pub fn is_worker_thread() -> bool {
+    IS_WORKER_THREAD.with(|w| w.get())
+}
+
 struct PanicCanary<'a> {
     flag: &'a atomic::AtomicBool
 }
@@ -146,29 +194,43 @@ impl Pool {
         let (tx, rx) = mpsc::channel::<Option<Job>>();
         let (finished_tx, finished_rx) = mpsc::channel();
 
+        // each worker gets a single inbox that carries both batch
+        // slices (from `execute`) and ad-hoc tasks (from `spawn`); a
+        // clone of each sender is kept on `Pool` itself, so `spawn`
+        // can reach a worker directly.
+        let mut task_txs = Vec::with_capacity(n_threads);
+        let mut subrxs = Vec::with_capacity(n_threads);
+        for _ in 0..n_threads {
+            let (subtx, subrx) = mpsc::channel::<WorkerMsg>();
+            task_txs.push(subtx);
+            subrxs.push(subrx);
+        }
+        let supervisor_txs = task_txs.clone();
+
         thread::spawn(move || {
             let ref panicked = atomic::AtomicBool::new(false);
 
             let mut _guards = Vec::with_capacity(n_threads);
-            let mut txs = Vec::with_capacity(n_threads);
             let finished_tx = PanicHandler {
                 tx: finished_tx,
             };
 
-            for i in 0..n_threads {
+            for (i, subrx) in subrxs.into_iter().enumerate() {
                 let id = WorkerId { n: i };
-                let (subtx, subrx) = mpsc::channel::<Work>();
-                txs.push(subtx);
 
                 _guards.push(thread::scoped(move || {
                     let _canary = PanicCanary {
                         flag: panicked
                     };
+                    IS_WORKER_THREAD.with(|w| w.set(true));
                     loop {
                         match subrx.recv() {
-                            Ok(mut work) => {
+                            Ok(WorkerMsg::Batch(mut work)) => {
                                 (work.func)(id)
                             }
+                            Ok(WorkerMsg::Task(task)) => {
+                                task.func.invoke(())
+                            }
                             Err(_) => break,
                         }
                     }
@@ -176,7 +238,7 @@ impl Pool {
             }
 
             while let Ok(Some(job)) = rx.recv() {
-                job.func.invoke(&txs);
+                job.func.invoke(&supervisor_txs);
                 let job_panicked = panicked.load(atomic::Ordering::SeqCst);
                 let msg = if job_panicked { Err(()) } else { Ok(()) };
                 finished_tx.tx.send(msg).unwrap();
@@ -189,93 +251,33 @@ impl Pool {
             job_queue: tx,
             job_finished: finished_rx,
             n_threads: n_threads,
+            task_txs: task_txs,
+            next_task_worker: atomic::AtomicUsize::new(0),
+            grain_size: DEFAULT_GRAIN_SIZE,
         }
     }
 
-    /// Execute `f` on each element of `iter`.
-    ///
-    /// This panics if `f` panics, although the precise time and
-    /// number of elements consumed after the element that panics is
-    /// not specified.
-    ///
-    /// # Examples
-    ///
-    /// ```rust
-    /// use simple_parallel::Pool;
-    ///
-    /// let mut pool = Pool::new(4);
-    ///
-    /// let mut v = [0; 8];
-    ///
-    /// // set each element, in parallel
-    /// pool.for_(&mut v, |element| *element = 3);
-    ///
-    /// assert_eq!(v, [3; 8]);
-    /// ```
-    pub fn for_<Iter: IntoIterator, F>(&mut self, iter: Iter, ref f: F)
-        where Iter::Item: Send,
-              Iter: Send,
-              F: Fn(Iter::Item) + Sync
-
-    {
-        let (needwork_tx, needwork_rx) = mpsc::channel();
-        let mut work_txs = Vec::with_capacity(self.n_threads);
-        let mut work_rxs = Vec::with_capacity(self.n_threads);
-        for _ in 0..self.n_threads {
-            let (t, r) = mpsc::channel();
-            work_txs.push(t);
-            work_rxs.push(r);
-        }
+    /// The number of worker threads in this pool.
+    pub fn n_threads(&self) -> usize { self.n_threads }
 
-        let mut work_rxs = work_rxs.into_iter();
+    /// The number of elements handed to a worker per synchronisation
+    /// round by the element-oriented jobs (`for_`, `map`,
+    /// `unordered_map`, `map_reduce`). Defaults to 32.
+    pub fn grain_size(&self) -> usize { self.grain_size }
 
-        unsafe {
-            let handle = self.execute(
-                needwork_tx,
-                |needwork_tx| {
-                    let mut needwork_tx = Some(needwork_tx.clone());
-                    let mut work_rx = Some(work_rxs.next().unwrap());
-                    move |id| {
-                        let work_rx = work_rx.take().unwrap();
-                        let needwork = needwork_tx.take().unwrap();
-                        loop {
-                            needwork.send(id).unwrap();
-                            match work_rx.recv() {
-                                Ok(Some(elem)) => {
-                                    f(elem);
-                                }
-                                Ok(None) | Err(_) => break
-                            }
-                        }
-                    }
-                },
-                move |needwork_tx| {
-                    let mut iter = iter.into_iter().fuse();
-                    drop(needwork_tx);
-                    loop {
-                        match needwork_rx.recv() {
-                            // closed, done!
-                            Err(_) => break,
-                            Ok(id) => {
-                                work_txs[id.n].send(iter.next()).unwrap();
-                            }
-                        }
-                    }
-                });
-
-            handle.wait();
-        }
-    }
-
-    /// Execute `f` on each element in `iter` in parallel across the
-    /// pool's threads, with unspecified yield order.
+    /// Set the number of elements handed to a worker per
+    /// synchronisation round by the element-oriented jobs (`for_`,
+    /// `map`, `unordered_map`, `map_reduce`).
     ///
-    /// This behaves like `map`, but does not make efforts to ensure
-    /// that the elements are returned in the order of `iter`, hence
-    /// this is cheaper.
+    /// A larger grain means fewer, bigger round-trips through the
+    /// work-stealing deques (less synchronisation overhead, but
+    /// coarser load balancing between workers); a smaller grain means
+    /// finer-grained load balancing at the cost of more frequent
+    /// deque traffic.
     ///
-    /// The iterator yields `(uint, T)` tuples, where the `uint` is
-    /// the index of the element in the original iterator.
+    /// # Panics
+    ///
+    /// Panics if `grain_size` is 0.
     ///
     /// # Examples
     ///
@@ -283,120 +285,23 @@ impl Pool {
     /// use simple_parallel::Pool;
     ///
     /// let mut pool = Pool::new(4);
+    /// pool.with_grain_size(1024);
     ///
-    /// // adjust each element in parallel, and iterate over them as
-    /// // they are generated (or as close to that as possible)
-    /// let f = |i| i + 10;
-    /// for (index, output) in pool.unordered_map(0..8, &f) {
-    ///     // each element is exactly 10 more than its original index
-    ///     assert_eq!(output, index + 10);
-    /// }
+    /// let v: Vec<_> = pool.map(0..10_000, &|x| x + 1).collect();
+    /// assert_eq!(v.len(), 10_000);
     /// ```
-    pub fn unordered_map<'pool, 'a, I: IntoIterator, F, T>(&'pool mut self, iter: I, f: &'a F)
-        -> UnorderedParMap<'pool, 'a, T>
-        where I: 'a + Send,
-              I::Item: Send + 'a,
-              F: 'a + Sync + Fn(I::Item) -> T,
-              T: Send + 'a
-    {
-        let (needwork_tx, needwork_rx) = mpsc::channel();
-        let mut work_txs = Vec::with_capacity(self.n_threads);
-        let mut work_rxs = Vec::with_capacity(self.n_threads);
-        for _ in 0..self.n_threads {
-            let (t, r) = mpsc::channel();
-            work_txs.push(t);
-            work_rxs.push(r);
-        }
-
-        let mut work_rxs = work_rxs.into_iter();
-
-        let (tx, rx) = mpsc::channel();
-
-        let handle = unsafe {
-            self.execute(needwork_tx,
-                         move |needwork_tx| {
-                             let mut needwork_tx = Some(needwork_tx.clone());
-                             let mut work_rx = Some(work_rxs.next().unwrap());
-                             let tx = tx.clone();
-                             move |id| {
-                                 let work_rx = work_rx.take().unwrap();
-                                 let needwork = needwork_tx.take().unwrap();
-                                 loop {
-                                     needwork.send(id).unwrap();
-                                     match work_rx.recv() {
-                                         Ok(Some((idx, elem))) => {
-                                             let data = f(elem);
-                                             let status = tx.send(Packet {
-                                                 idx: idx, data: Some(data)
-                                             });
-                                             // the user disconnected,
-                                             // so there's no point
-                                             // computing more.
-                                             if status.is_err() {
-                                                 break
-                                             }
-                                         }
-                                         Ok(None) | Err(_) => break
-                                     }
-                                 }
-                             }
-                         },
-                         move |needwork_tx| {
-                             let mut iter = iter.into_iter().fuse().enumerate();
-                             drop(needwork_tx);
-                             loop {
-                                 match needwork_rx.recv() {
-                                     // closed, done!
-                                     Err(_) => break,
-                                     Ok(id) => {
-                                         work_txs[id.n].send(iter.next()).unwrap();
-                                     }
-                                 }
-                             }
-                         })
-        };
-
-        UnorderedParMap {
-            rx: rx,
-            _guard: handle,
-        }
+    pub fn with_grain_size(&mut self, grain_size: usize) -> &mut Pool {
+        assert!(grain_size > 0, "grain_size must be positive");
+        self.grain_size = grain_size;
+        self
     }
 
-    /// Execute `f` on `iter` in parallel across the pool's threads,
-    /// returning an iterator that yields the results in the order of
-    /// the elements of `iter` to which they correspond.
-    ///
-    /// This is a drop-in replacement for `iter.map(f)`, that runs in
-    /// parallel, and consumes `iter` as the pool's threads complete
-    /// their previous tasks.
-    ///
-    /// See `unordered_map` if the output order is unimportant.
-    ///
-    /// # Examples
-    ///
-    /// ```rust
-    /// use simple_parallel::Pool;
-    ///
-    /// let mut pool = Pool::new(4);
-    ///
-    /// // create a vector by adjusting 0..8, in parallel
-    /// let f = |i| i + 10;
-    /// let elements: Vec<_> = pool.map(0..8, &f).collect();
-    ///
-    /// assert_eq!(elements, &[10, 11, 12, 13, 14, 15, 16, 17]);
-    /// ```
-    pub fn map<'pool, 'a, I: IntoIterator, F, T>(&'pool mut self, iter: I, f: &'a F)
-        -> ParMap<'pool, 'a, T>
-        where I: 'a + Send,
-              I::Item: Send + 'a,
-              F: 'a + Sync + Fn(I::Item) -> T,
-              T: Send + 'a
-    {
-        ParMap {
-            unordered: self.unordered_map(iter, f),
-            looking_for: 0,
-            queue: BinaryHeap::new(),
-        }
+    /// Send `task` directly to one of the pool's worker inboxes,
+    /// round-robin, bypassing `job_queue` entirely so it can run
+    /// alongside (or in between) batch jobs.
+    pub fn dispatch_task(&self, task: Task) {
+        let i = self.next_task_worker.fetch_add(1, atomic::Ordering::SeqCst) % self.task_txs.len();
+        self.task_txs[i].send(WorkerMsg::Task(task)).unwrap();
     }
 }
 
@@ -439,7 +344,7 @@ impl Pool {
         // transmutes scary? only a little: the returned `JobHandle`
         // ensures safety by connecting this job to the outside stack
         // frame.
-        let func: JobInner<'f> = Box::new(move |workers: &[mpsc::Sender<Work>]| {
+        let func: JobInner<'f> = Box::new(move |workers: &[mpsc::Sender<WorkerMsg>]| {
             assert_eq!(workers.len(), n_threads);
             let mut worker_fns: Vec<_> = (0..n_threads).map(|_| gen_fn(&mut data)).collect();
 
@@ -448,7 +353,7 @@ impl Pool {
                 let func: WorkInner<'static> = unsafe {
                     mem::transmute(func)
                 };
-                worker.send(Work { func: func }).unwrap();
+                worker.send(WorkerMsg::Batch(Work { func: func })).unwrap();
             }
 
             main_fn(data)
@@ -465,79 +370,3 @@ impl Pool {
         }
     }
 }
-
-
-use std::cmp::Ordering;
-
-struct Packet<T> {
-    // this should be unique for a given instance of `*ParMap`
-    idx: usize,
-    data: Option<T>,
-}
-impl<T> PartialOrd for Packet<T> {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> { Some(self.cmp(other)) }
-}
-impl<T> Ord for Packet<T> {
-    // reverse the ordering, to work with the max-heap
-    fn cmp(&self, other: &Self) -> Ordering { other.idx.cmp(&self.idx) }
-}
-impl<T> PartialEq for Packet<T> {
-    fn eq(&self, other: &Self) -> bool { self.idx == other.idx }
-}
-impl<T> Eq for Packet<T> {}
-
-/// A parallel-mapping iterator, that yields elements in the order
-/// they are computed, not the order from which they are yielded by
-/// the underlying iterator.
-pub struct UnorderedParMap<'pool, 'a, T: 'a + Send> {
-    rx: mpsc::Receiver<Packet<T>>,
-    _guard: JobHandle<'pool, 'a>,
-}
-impl<'pool, 'a,T: 'a + Send> Iterator for UnorderedParMap<'pool , 'a, T> {
-    type Item = (usize, T);
-
-    fn next(&mut self) -> Option<(usize, T)> {
-        match self.rx.recv() {
-            Ok(Packet { data: Some(x), idx }) => Some((idx, x)),
-            Ok(Packet { data: None, .. }) => {
-                panic!("simple_parallel::unordered_map: closure panicked")
-            }
-            Err(mpsc::RecvError) => None,
-        }
-    }
-}
-
-/// A parallel-mapping iterator, that yields elements in the order
-/// they are yielded by the underlying iterator.
-pub struct ParMap<'pool, 'a, T: 'a + Send> {
-    unordered: UnorderedParMap<'pool, 'a, T>,
-    looking_for: usize,
-    queue: BinaryHeap<Packet<T>>
-}
-
-impl<'pool, 'a, T: Send + 'a> Iterator for ParMap<'pool, 'a, T> {
-    type Item = T;
-
-    fn next(&mut self) -> Option<T> {
-        loop {
-            if self.queue.peek().map_or(false, |x| x.idx == self.looking_for) {
-                // we've found what we want, so lets return it
-
-                let packet = self.queue.pop().unwrap();
-                self.looking_for += 1;
-                match packet.data {
-                    Some(x) => return Some(x),
-                    None => panic!("simple_parallel::map: closure panicked")
-                }
-            }
-            match self.unordered.rx.recv() {
-                // this could be optimised to check for `packet.idx ==
-                // self.looking_for` to avoid the BinaryHeap
-                // interaction if its what we want.
-                Ok(packet) => self.queue.push(packet),
-                // all done
-                Err(mpsc::RecvError) => return None,
-            }
-        }
-    }
-}
@@ -0,0 +1,115 @@
+use std::{marker, mem};
+use std::sync::mpsc;
+use std::thread;
+
+use pool::{Pool, Task, TaskInner};
+
+/// A handle to a closure submitted via `Pool::spawn`.
+///
+/// Dropping this (without calling `wait`/`join` first) blocks until
+/// the closure has finished running; that's what makes it sound for
+/// the closure to borrow from the caller's stack frame, the same
+/// lifetime trick `JobHandle` uses for the batch APIs.
+///
+/// If the closure panics, `wait`/`join` (or the destructor) will
+/// panic too.
+pub struct JobFuture<'f, T> {
+    rx: mpsc::Receiver<T>,
+    done: bool,
+    _marker: marker::PhantomData<&'f ()>,
+}
+impl<'f, T> JobFuture<'f, T> {
+    /// Block until the closure has finished, and return its result.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if the closure panicked.
+    pub fn wait(mut self) -> T {
+        self.done = true;
+        match self.rx.recv() {
+            Ok(t) => t,
+            // the sending end hung up without ever sending, which
+            // only happens if the closure unwound.
+            Err(mpsc::RecvError) => panic!("simple_parallel::spawn: closure panicked"),
+        }
+    }
+
+    /// An alias for `wait`, to match `thread::JoinHandle::join`.
+    pub fn join(self) -> T {
+        self.wait()
+    }
+}
+#[unsafe_destructor]
+impl<'f, T> Drop for JobFuture<'f, T> {
+    fn drop(&mut self) {
+        if !self.done {
+            // don't start a second panic while already unwinding.
+            if self.rx.recv().is_err() && !thread::panicking() {
+                panic!("simple_parallel::spawn: closure panicked");
+            }
+        }
+    }
+}
+
+impl Pool {
+    /// Submit `f` to run on one of the pool's workers, without taking
+    /// over the whole pool like `for_`/`map` do.
+    ///
+    /// The worker is chosen round-robin, not by which one is free:
+    /// `f` waits behind whatever is already in that worker's inbox
+    /// (including its share of a running batch job), even if other
+    /// workers are idle.
+    ///
+    /// This returns a `JobFuture` that can later be used to retrieve
+    /// `f`'s result with `.wait()`/`.join()`; as long as that handle
+    /// is kept around, `f` (and anything it borrows from the calling
+    /// stack frame) is guaranteed to still be valid, exactly as with
+    /// the batch APIs. This is what lets one incrementally submit
+    /// jobs to build pipeline/producer-consumer patterns, unlike
+    /// `for_`/`map`/`unordered_map`, which each take control of the
+    /// whole pool for the duration of a single batch.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use simple_parallel::Pool;
+    ///
+    /// let mut pool = Pool::new(4);
+    ///
+    /// let x = 1;
+    /// let future = pool.spawn(|| x + 1);
+    /// assert_eq!(future.wait(), 2);
+    /// ```
+    pub fn spawn<'f, F, T>(&self, f: F) -> JobFuture<'f, T>
+        where F: 'f + FnOnce() -> T + Send, T: 'f + Send
+    {
+        let (tx, rx) = mpsc::channel();
+
+        let task: TaskInner<'f> = Box::new(move |_: ()| {
+            // if `f` panics, this whole closure unwinds without ever
+            // reaching the `send`, so `rx.recv()` in `wait` correctly
+            // sees the channel hang up with nothing sent.
+            let result = f();
+            let _ = tx.send(result);
+        });
+        let task: TaskInner<'static> = unsafe { mem::transmute(task) };
+
+        self.dispatch_task(Task { func: task });
+
+        JobFuture {
+            rx: rx,
+            done: false,
+            _marker: marker::PhantomData,
+        }
+    }
+
+    /// Like `spawn`, but for a `'static` closure whose result isn't
+    /// needed: fire it off onto one of the pool's workers (chosen
+    /// round-robin, as for `spawn`) and forget it.
+    pub fn spawn_detached<F>(&self, f: F)
+        where F: 'static + FnOnce() + Send
+    {
+        let task: TaskInner<'static> = Box::new(move |_: ()| f());
+        self.dispatch_task(Task { func: task });
+    }
+}